@@ -9,7 +9,13 @@ use bevy::{
 };
 use bevy_aseprite_ultra::prelude::*;
 use bevy_wave_function_collapse_aseprite::Grid;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use rand::{rngs::StdRng, Rng};
+use std::{
+    path::Path,
+    sync::mpsc::{channel, Receiver},
+    time::{Duration, Instant},
+};
 
 /// 生成するグリッドの縦横のセル数
 const DIMENSION: usize = 16;
@@ -17,12 +23,38 @@ const DIMENSION: usize = 16;
 /// タイルの縦横のピクセルサイズ
 const TILE_SIZE: u32 = 16;
 
+/// 監視対象のAsepriteファイルのパス(`assets/`からの相対パス)
+const ASEPRITE_PATH: &str = "image.aseprite";
+
+/// ファイルの保存イベントが連続しても、1回の再生成とみなすためのデバウンス時間
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(300);
+
 #[derive(Resource)]
 pub struct SourceImage(Handle<Aseprite>);
 
 #[derive(Component)]
 struct WaveFunctionCollapseTask(Task<CommandQueue>);
 
+/// 波動関数崩壊のシード値です。ファイルのホットリロードをまたいで保持され、
+/// `R`キーを押したときだけ振り直されるので、編集中のタイルを安定したレイアウトで確認できます
+#[derive(Resource)]
+struct WfcSeed([u8; 32]);
+
+impl Default for WfcSeed {
+    fn default() -> Self {
+        WfcSeed([13; 32])
+    }
+}
+
+/// `image.aseprite` を監視するファイルシステムウォッチャーです。ウォッチャー自体を
+/// ドロップさせないためにリソースとして保持し、イベントはチャンネル経由で毎フレーム読み出します
+#[derive(Resource)]
+struct AsepriteFileWatcher {
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<notify::Event>>,
+    last_reload: Option<Instant>,
+}
+
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins.set(ImagePlugin {
@@ -34,7 +66,7 @@ fn main() {
             Update,
             run_wave_function_collupse_task.run_if(resource_exists::<SourceImage>),
         )
-        .add_systems(Update, rebuild)
+        .add_systems(Update, (rebuild, hot_reload, reroll_seed))
         .add_systems(Update, handle_tasks)
         .run();
 }
@@ -49,7 +81,26 @@ fn setup(mut commands: Commands, server: Res<AssetServer>) {
         )
         .with_scale(Vec3::splat(0.4)),
     ));
-    commands.insert_resource(SourceImage(server.load("image.aseprite")));
+    commands.insert_resource(SourceImage(server.load(ASEPRITE_PATH)));
+    commands.init_resource::<WfcSeed>();
+    commands.insert_resource(spawn_aseprite_watcher());
+}
+
+/// `assets/image.aseprite` を監視するウォッチャーを起動します
+fn spawn_aseprite_watcher() -> AsepriteFileWatcher {
+    let (tx, rx) = channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).expect("failed to create aseprite file watcher");
+    let watched_path = Path::new("assets").join(ASEPRITE_PATH);
+    watcher
+        .watch(&watched_path, RecursiveMode::NonRecursive)
+        .expect("failed to watch image.aseprite for changes");
+
+    AsepriteFileWatcher {
+        _watcher: watcher,
+        receiver: rx,
+        last_reload: None,
+    }
 }
 
 fn rebuild(
@@ -59,16 +110,66 @@ fn rebuild(
     query: Query<Entity, With<AseSpriteSlice>>,
 ) {
     if mouse.just_pressed(MouseButton::Left) {
-        commands.insert_resource(SourceImage(server.load("image.aseprite")));
-        for entity in query.iter() {
-            commands.entity(entity).despawn_recursive();
+        request_reload(&mut commands, &server, &query);
+    }
+}
+
+/// Asepriteファイルの変更を検知したら、デバウンスを挟んで自動的に再生成します。
+/// アーティストがAseprite上でタイルを編集して保存するだけで、WFCの出力が更新されます
+fn hot_reload(
+    mut commands: Commands,
+    server: Res<AssetServer>,
+    query: Query<Entity, With<AseSpriteSlice>>,
+    mut watcher: ResMut<AsepriteFileWatcher>,
+) {
+    let mut modified = false;
+    while let Ok(event) = watcher.receiver.try_recv() {
+        if event.map(|event| event.kind.is_modify()).unwrap_or(false) {
+            modified = true;
         }
     }
+
+    if !modified {
+        return;
+    }
+
+    let now = Instant::now();
+    if watcher
+        .last_reload
+        .is_some_and(|last| now.duration_since(last) < RELOAD_DEBOUNCE)
+    {
+        return;
+    }
+    watcher.last_reload = Some(now);
+
+    request_reload(&mut commands, &server, &query);
+}
+
+fn request_reload(
+    commands: &mut Commands,
+    server: &AssetServer,
+    query: &Query<Entity, With<AseSpriteSlice>>,
+) {
+    commands.insert_resource(SourceImage(server.load(ASEPRITE_PATH)));
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// `R`キーでシードを振り直します。押さなければ、ファイルを編集してリロードしても
+/// 同じレイアウトが再現されるので、変更の影響だけを見比べられます
+fn reroll_seed(keyboard: Res<ButtonInput<KeyCode>>, mut seed: ResMut<WfcSeed>) {
+    if keyboard.just_pressed(KeyCode::KeyR) {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill(&mut bytes);
+        seed.0 = bytes;
+    }
 }
 
 fn run_wave_function_collupse_task(
     mut commands: Commands,
     source: Res<SourceImage>,
+    seed: Res<WfcSeed>,
     aseprites: Res<Assets<Aseprite>>,
     images: Res<Assets<Image>>,
 ) {
@@ -84,13 +185,13 @@ fn run_wave_function_collupse_task(
             // ライフタイムの問題により非同期タスクの内部では実行できません
             // ここから非同期タスクを開始します
             let aseplite_cloned = source.0.clone();
+            let seed = seed.0;
             let thread_pool = AsyncComputeTaskPool::get();
             let entity = commands.spawn_empty().id();
             let task: Task<CommandQueue> = thread_pool.spawn(async move {
-                // 結果を再現可能にするにはシードを指定して乱数生成器を初期化します
-                // let seed: [u8; 32] = [42; 32];
-                // let mut rng = rand::SeedableRng::from_seed(seed);
-                let mut rng: StdRng = rand::SeedableRng::from_entropy();
+                // ホットリロードをまたいでも同じレイアウトが再現されるよう、
+                // `WfcSeed` に保持されたシードで乱数生成器を初期化します
+                let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
 
                 // 行き止まりの通路が生成されないように、外周のセルを空白タイルにします
                 // また、通路や部屋の密度が高くなりすぎないように、ランダムに空白タイルを設定します
@@ -0,0 +1,372 @@
+//! GPUを使った制約伝播のオプションバックエンドです
+//!
+//! `dimension` が大きいグリッドでは [`crate::Grid::collapse_with`] のCPU側の伝播がボトルネックに
+//! なるため、各タイルの `up/right/down/left` 隣接リストと各セルの `sockets` をビットマスクに
+//! パックしてstorageバッファへアップロードし、コンピュートシェーダ (`wfc_propagate.wgsl`) で
+//! 近傍との交差計算を並列に行います。小さな盤面では従来どおりCPUパスがデフォルトのままです。
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, BindingType, Buffer,
+            BufferBindingType, BufferDescriptor, BufferInitDescriptor, BufferUsages,
+            CachedComputePipelineId, CommandEncoderDescriptor, ComputePassDescriptor,
+            ComputePipelineDescriptor, Maintain, MapMode, PipelineCache, ShaderStages, ShaderType,
+        },
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+
+use crate::{Cell, Tileset};
+
+pub const WFC_PROPAGATE_SHADER_PATH: &str = "shaders/wfc_propagate.wgsl";
+
+/// 1タイルあたりのビットマスクの幅(32タイルごとに1語)
+pub fn mask_word_count(tile_count: usize) -> usize {
+    (tile_count + 31) / 32
+}
+
+/// GPU側のコンピュートシェーダに渡すパラメータで、`Params` (wgsl側)と1対1で対応します
+#[derive(Clone, Copy, ShaderType)]
+pub struct GpuWfcParams {
+    pub dimension: u32,
+    pub tile_count: u32,
+    pub words: u32,
+}
+
+/// タイルの `up`/`right`/`down`/`left` のいずれかの隣接リストを、
+/// タイルごとのビットマスクの列にパックします。シェーダ側の
+/// `adjacency_up`/`adjacency_right`/`adjacency_down`/`adjacency_left` に対応します
+pub fn pack_adjacency_masks(tileset: &Tileset, direction: &str) -> Vec<u32> {
+    let words = mask_word_count(tileset.tiles.len());
+    let mut packed = vec![0u32; tileset.tiles.len() * words];
+
+    for (tile_index, tile) in tileset.tiles.iter().enumerate() {
+        let list = match direction {
+            "up" => &tile.up,
+            "right" => &tile.right,
+            "down" => &tile.down,
+            "left" => &tile.left,
+            _ => continue,
+        };
+        for &allowed in list {
+            packed[tile_index * words + allowed / 32] |= 1 << (allowed % 32);
+        }
+    }
+
+    packed
+}
+
+/// グリッド全体のセルの `sockets` を1本のビットマスク配列にパックします
+pub fn pack_cell_masks(cells: &[Cell], tile_count: usize) -> Vec<u32> {
+    let words = mask_word_count(tile_count);
+    let mut packed = vec![0u32; cells.len() * words];
+
+    for cell in cells {
+        for &socket in &cell.sockets {
+            packed[cell.index * words + socket / 32] |= 1 << (socket % 32);
+        }
+    }
+
+    packed
+}
+
+/// パックされたビットマスクから、そのセルの候補タイルのインデックス一覧を取り出します
+pub fn unpack_cell_mask(mask: &[u32]) -> Vec<usize> {
+    let mut sockets = Vec::new();
+    for (word_index, word) in mask.iter().enumerate() {
+        for bit in 0..32 {
+            if word & (1 << bit) != 0 {
+                sockets.push(word_index * 32 + bit);
+            }
+        }
+    }
+    sockets
+}
+
+/// GPU伝播に使うstorageバッファ一式です。呼び出し側が [`GpuWfcBuffers::new`] で確保し、
+/// セルが1つ崩壊するたびにCPU側で内容を書き換えて再アップロードします
+#[derive(Resource)]
+pub struct GpuWfcBuffers {
+    pub params: Buffer,
+    pub cell_masks: Buffer,
+    pub adjacency_up: Buffer,
+    pub adjacency_right: Buffer,
+    pub adjacency_down: Buffer,
+    pub adjacency_left: Buffer,
+    pub changed: Buffer,
+    pub changed_readback: Buffer,
+    pub cell_masks_readback: Buffer,
+}
+
+impl GpuWfcBuffers {
+    pub fn new(device: &RenderDevice, tileset: &Tileset, dimension: usize) -> Self {
+        let tile_count = tileset.tiles.len();
+        let words = mask_word_count(tile_count);
+
+        let params = GpuWfcParams {
+            dimension: dimension as u32,
+            tile_count: tile_count as u32,
+            words: words as u32,
+        };
+
+        let make_storage = |label: &'static str, contents: &[u32]| {
+            device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(contents),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            })
+        };
+
+        let zeroed_cells = vec![0u32; dimension * dimension * words];
+
+        Self {
+            params: device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("wfc_params"),
+                contents: bytemuck::bytes_of(&params),
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            }),
+            cell_masks: make_storage("wfc_cell_masks", &zeroed_cells),
+            adjacency_up: make_storage("wfc_adjacency_up", &pack_adjacency_masks(tileset, "up")),
+            adjacency_right: make_storage(
+                "wfc_adjacency_right",
+                &pack_adjacency_masks(tileset, "right"),
+            ),
+            adjacency_down: make_storage(
+                "wfc_adjacency_down",
+                &pack_adjacency_masks(tileset, "down"),
+            ),
+            adjacency_left: make_storage(
+                "wfc_adjacency_left",
+                &pack_adjacency_masks(tileset, "left"),
+            ),
+            changed: make_storage("wfc_changed", &[0u32]),
+            changed_readback: device.create_buffer(&BufferDescriptor {
+                label: Some("wfc_changed_readback"),
+                size: std::mem::size_of::<u32>() as u64,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            cell_masks_readback: device.create_buffer(&BufferDescriptor {
+                label: Some("wfc_cell_masks_readback"),
+                size: (dimension * dimension * words * std::mem::size_of::<u32>()) as u64,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    /// グリッド全体のセルの `sockets` を書き換え、GPU側のバッファへアップロードし直します。
+    /// `Grid::collapse_gpu` が1セルを崩壊させるたびに呼び出します
+    pub fn upload_cell_masks(&self, queue: &RenderQueue, cells: &[Cell], tile_count: usize) {
+        let packed = pack_cell_masks(cells, tile_count);
+        queue.write_buffer(&self.cell_masks, 0, bytemuck::cast_slice(&packed));
+    }
+}
+
+/// [`GpuWfcBuffers`] と [`GpuWfcPipeline`] を使い、"changed" フラグが立たなくなるまで
+/// 伝播のコンピュートパイプラインを繰り返しディスパッチします。完了したら全セルの
+/// マスクを読み戻し、いずれかのセルのマスクが空になっていた場合は矛盾として `None` を返します。
+/// パイプラインのコンパイルがまだ終わっていない場合も、ディスパッチせずに `None` を返します
+pub fn dispatch_propagation(
+    device: &RenderDevice,
+    queue: &RenderQueue,
+    pipeline_cache: &PipelineCache,
+    pipeline: &GpuWfcPipeline,
+    buffers: &GpuWfcBuffers,
+    dimension: usize,
+    tile_count: usize,
+) -> Option<Vec<Vec<usize>>> {
+    let compute_pipeline = pipeline_cache.get_compute_pipeline(pipeline.pipeline_id)?;
+
+    let bind_group = device.create_bind_group(
+        "wfc_propagate_bind_group",
+        &pipeline.layout,
+        &BindGroupEntries::sequential((
+            buffers.params.as_entire_binding(),
+            buffers.cell_masks.as_entire_binding(),
+            buffers.adjacency_up.as_entire_binding(),
+            buffers.adjacency_right.as_entire_binding(),
+            buffers.adjacency_down.as_entire_binding(),
+            buffers.adjacency_left.as_entire_binding(),
+            buffers.changed.as_entire_binding(),
+        )),
+    );
+
+    let workgroups = (dimension as u32).div_ceil(8);
+
+    loop {
+        queue.write_buffer(&buffers.changed, 0, bytemuck::bytes_of(&0u32));
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("wfc_propagate_encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("wfc_propagate_pass"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(compute_pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(workgroups, workgroups, 1);
+        }
+        encoder.copy_buffer_to_buffer(
+            &buffers.changed,
+            0,
+            &buffers.changed_readback,
+            0,
+            std::mem::size_of::<u32>() as u64,
+        );
+        queue.submit(Some(encoder.finish()));
+        device.poll(Maintain::Wait);
+
+        if read_u32(&buffers.changed_readback, device) == 0 {
+            break;
+        }
+    }
+
+    let words = mask_word_count(tile_count);
+    let cell_count = dimension * dimension;
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("wfc_readback_encoder"),
+    });
+    encoder.copy_buffer_to_buffer(
+        &buffers.cell_masks,
+        0,
+        &buffers.cell_masks_readback,
+        0,
+        (cell_count * words * std::mem::size_of::<u32>()) as u64,
+    );
+    queue.submit(Some(encoder.finish()));
+    device.poll(Maintain::Wait);
+
+    let packed = read_u32_slice(&buffers.cell_masks_readback, device, cell_count * words);
+
+    let mut sockets_per_cell = Vec::with_capacity(cell_count);
+    for cell_index in 0..cell_count {
+        let mask = &packed[cell_index * words..(cell_index + 1) * words];
+        let sockets = unpack_cell_mask(mask);
+        if sockets.is_empty() {
+            return None;
+        }
+        sockets_per_cell.push(sockets);
+    }
+
+    Some(sockets_per_cell)
+}
+
+/// 1個の `u32` だけを持つ読み戻し用バッファの中身を同期的に取り出します
+fn read_u32(buffer: &Buffer, device: &RenderDevice) -> u32 {
+    read_u32_slice(buffer, device, 1)[0]
+}
+
+/// 読み戻し用バッファの中身を `u32` の列として同期的に取り出します。
+/// `map_async` の完了を `RenderDevice::poll` で待ってから読み取ります
+fn read_u32_slice(buffer: &Buffer, device: &RenderDevice, len: usize) -> Vec<u32> {
+    let slice = buffer.slice(..);
+    slice.map_async(MapMode::Read, |_| {});
+    device.poll(Maintain::Wait);
+
+    let view = slice.get_mapped_range();
+    let values: Vec<u32> = bytemuck::cast_slice(&view)[..len].to_vec();
+    drop(view);
+    buffer.unmap();
+    values
+}
+
+/// [`GpuWfcBuffers`] のバインドグループレイアウトとキャッシュされたパイプラインIDです
+#[derive(Resource)]
+pub struct GpuWfcPipeline {
+    pub layout: BindGroupLayout,
+    pub pipeline_id: CachedComputePipelineId,
+}
+
+impl FromWorld for GpuWfcPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
+
+        let layout = device.create_bind_group_layout(
+            "wfc_propagate_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::COMPUTE,
+                (
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                ),
+            ),
+        );
+
+        let shader = world.resource::<AssetServer>().load(WFC_PROPAGATE_SHADER_PATH);
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("wfc_propagate_pipeline".into()),
+            layout: vec![layout.clone()],
+            push_constant_ranges: Vec::new(),
+            shader,
+            shader_defs: Vec::new(),
+            entry_point: "propagate".into(),
+            zero_initialize_workgroup_memory: false,
+        });
+
+        Self {
+            layout,
+            pipeline_id,
+        }
+    }
+}
+
+/// レンダーサブアプリに [`GpuWfcPipeline`] を用意するプラグインです。CPUパス
+/// ([`crate::Grid::collapse_with`]) は変わらず既定のまま利用できます。
+///
+/// このプラグインはパイプラインの確保だけを行い、伝播そのものを駆動する`Render`スケジュールの
+/// システムは登録しません。GPUバックエンドの入り口は [`crate::Grid::collapse_gpu`]で、
+/// 呼び出し側がセルを1つ崩壊させるたびに`device`/`queue`/`pipeline_cache`とともに明示的に
+/// 呼び出してディスパッチします
+pub struct GpuWfcPlugin;
+
+impl Plugin for GpuWfcPlugin {
+    fn build(&self, _app: &mut App) {}
+
+    fn finish(&self, app: &mut App) {
+        use bevy::render::RenderApp;
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.init_resource::<GpuWfcPipeline>();
+        }
+    }
+}
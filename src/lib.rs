@@ -3,7 +3,28 @@
 
 use bevy::prelude::*;
 use bevy_aseprite_ultra::prelude::{AseSpriteSlice, Aseprite};
-use rand::{prelude::SliceRandom, rngs::StdRng};
+use rand::{rngs::StdRng, Rng};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Bevyのstorageバッファとコンピュートシェーダを使った、伝播のGPUバックエンド(任意機能)
+#[cfg(feature = "gpu")]
+pub mod gpu;
+
+/// 1枚のサンプル画像から学習する重なり合い(overlapping)モデル
+pub mod overlapping;
+
+/// タイルを描画する際に元のスライスへ適用する90度単位の回転です
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+impl Rotation {
+    const ALL: [Rotation; 4] = [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270];
+}
 
 #[derive(Debug, Clone)]
 pub struct Tile {
@@ -14,6 +35,68 @@ pub struct Tile {
     pub right: Vec<usize>,
     pub down: Vec<usize>,
     pub left: Vec<usize>,
+
+    /// このタイルが選択される相対的な重み。元画像の中で同じ絵柄が何回出現したかに対応し、
+    /// ありふれたタイルほど大きく、珍しいタイルほど小さくなります
+    pub weight: f64,
+
+    /// 元のスライスに対して適用する回転。手描きされていない向きのバリアントを
+    /// 表現するために使います (`generate_symmetry_variants` 参照)
+    pub rotation: Rotation,
+
+    /// 元のスライスに対して左右反転を適用するかどうか
+    pub flip: bool,
+}
+
+/// スライスの対称性クラスです。古典的なWFCの実装に倣い、回転・反転がどの程度
+/// 絵柄を変えないかに応じて `X`(全対称)、`I`、`Diagonal`、`T`、`L` の5段階に分類します。
+/// [`Tileset::new_with_symmetry_overrides`] に渡すと、そのクラスが許す数だけ
+/// バリアントを機械的に生成し、残りはピクセル比較による自動重複排除をスキップします
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symmetry {
+    /// 回転・反転のいずれでも絵柄が変わらない(無地の床など)。バリアントは生成されません
+    X,
+    /// 180度回転や反転で同じ絵柄になる(まっすぐな通路など)。2バリアント
+    I,
+    /// 対角線で反転しても同じ絵柄になる。90度回転したものと合わせて2バリアント
+    Diagonal,
+    /// 左右反転では変わらないがT字型のように回転すると変わる。4バリアント
+    T,
+    /// L字コーナーのように、ある向きの反転がもう一方の回転と同じ絵柄になる。4バリアント
+    L,
+}
+
+impl Symmetry {
+    /// このクラスが許容する、元のスライスを含めたバリアントの総数です
+    fn variant_count(self) -> usize {
+        match self {
+            Symmetry::X => 1,
+            Symmetry::I | Symmetry::Diagonal => 2,
+            Symmetry::T | Symmetry::L => 4,
+        }
+    }
+
+    /// 元のスライス(回転なし・反転なし)を除いた、残りのバリアントが取るべき
+    /// `(回転, 反転)` の組です。ダイヘドラル群の軌道として対称性クラスごとに
+    /// 決まっていて、`variant_count() - 1` 個の要素を持ちます
+    fn variant_transforms(self) -> &'static [(Rotation, bool)] {
+        use Rotation::*;
+        match self {
+            Symmetry::X => &[],
+            // まっすぐな通路のように180度回転や反転で絵柄が変わらない場合、
+            // 残る向きは90度回転だけ
+            Symmetry::I => &[(R90, false)],
+            // 対角線の反転が自分自身と一致するため、(R0,flip)と(R90,false)は同じ絵柄になり
+            // (R90,flip)も元のスライスと重複する。残る独立な向きは90度回転のみ
+            Symmetry::Diagonal => &[(R90, false)],
+            // 左右反転では変わらずT字型のように回転で変わる場合、反転は冗長なので
+            // 4方向の回転だけを使う
+            Symmetry::T => &[(R90, false), (R180, false), (R270, false)],
+            // L字コーナーは、ある向きの反転がもう一方の回転と同じ絵柄になるため、
+            // 反転を足しても新しいバリアントは増えず、Tと同じ4方向の回転だけになる
+            Symmetry::L => &[(R90, false), (R180, false), (R270, false)],
+        }
+    }
 }
 
 impl Tile {
@@ -25,6 +108,24 @@ impl Tile {
             right: Vec::new(),
             down: Vec::new(),
             left: Vec::new(),
+            weight: 1.0,
+            rotation: Rotation::R0,
+            flip: false,
+        }
+    }
+
+    /// 既存のタイルと同じスライスを使いつつ、回転・反転だけが異なるバリアントを作ります
+    pub fn with_variant(base: &Tile, rotation: Rotation, flip: bool) -> Tile {
+        Tile {
+            slice_name: base.slice_name.clone(),
+            rect: base.rect,
+            up: Vec::new(),
+            right: Vec::new(),
+            down: Vec::new(),
+            left: Vec::new(),
+            weight: 1.0,
+            rotation,
+            flip,
         }
     }
 }
@@ -35,17 +136,38 @@ pub struct Tileset {
     pub tile_size: u32,
 }
 
+/// 完全なやり直しに切り替えるまでに許容するバックトラックの回数の既定値です
+/// (回数はやり直すたびに0から数え直します)
+pub const DEFAULT_MAX_BACKTRACKS: usize = 10_000;
+
 #[derive(Clone)]
 pub struct Grid {
     pub tileset: Tileset,
     pub cells: Vec<Cell>,
     pub dimension: usize,
+
+    /// 逐次的な巻き戻しで消費してよいバックトラックの回数の上限です。これを使い切ると
+    /// 逐次的な巻き戻しを諦め、最初から完全にやり直すことで無限ループを防ぎます
+    pub max_backtracks: usize,
 }
 
 impl Tileset {
     /// Asepriteファイルと画像からタイルセットを生成します
     /// スライスのサイズはすべて統一されている必要があります
     pub fn new(aseprite: &Aseprite, image: &Image) -> Self {
+        Self::new_with_symmetry_overrides(aseprite, image, &HashMap::new())
+    }
+
+    /// [`Tileset::new`] と同様にタイルセットを構築しますが、`symmetries` に挙げたスライス名に
+    /// ついては、ピクセル比較による自動重複排除の代わりに指定した [`Symmetry`] クラスが許す
+    /// 数だけバリアントを機械的に生成し、重みを均等に割ります。Asepriteのスライスにユーザー
+    /// データとして対称性クラスを埋め込む運用を想定した入り口で、1枚のコーナータイルを
+    /// 描くだけで`L`クラスから4方向ぶんのバリアントが得られる、といった使い方をします
+    pub fn new_with_symmetry_overrides(
+        aseprite: &Aseprite,
+        image: &Image,
+        symmetries: &HashMap<String, Symmetry>,
+    ) -> Self {
         // ソースの画像の読み込みが完了したらタイルを初期化
         let mut tiles: Vec<Tile> = Vec::new();
 
@@ -65,13 +187,84 @@ impl Tileset {
         // 通路のない空白のタイルが0番目になるようにソートします
         tiles.sort_by(|a, b| a.slice_name.cmp(&b.slice_name));
 
+        // 手描きされていない回転・反転のバリアントを生成し、タイルセットを拡張します
+        generate_symmetry_variants(&mut tiles, &image, tile_size, symmetries);
+
         let mut tileset = Tileset { tiles, tile_size };
 
         // 隣接関係を生成します
         generating_adjacency_rules(&mut tileset, &image, tile_size);
 
+        // 同じ隣接シグネチャ(≒同じ絵柄)を持つタイルの数を重みとして設定します。
+        // 対称性クラスが指定されたスライス(とそのバリアント)は、`generate_symmetry_variants`
+        // が割り当てた均等な重みを維持するためここでは上書きしません
+        let symmetry_slices: HashSet<&str> = symmetries.keys().map(String::as_str).collect();
+        assign_weights_by_frequency(&mut tileset, &symmetry_slices);
+
+        tileset
+    }
+
+    /// [`Tileset::new`] と同様にタイルセットを構築しますが、`overrides` に挙げたスライス名に
+    /// ついては頻度から自動計算した重みではなく、ここで指定した値を使います。Asepriteのスライスに
+    /// ユーザーデータとして重みを埋め込む運用(例えば "rare feature" タイルを意図的に下げる)を
+    /// 想定した入り口で、同じスライスから生成された回転・反転バリアントにも同じ重みが適用されます
+    pub fn new_with_weight_overrides(
+        aseprite: &Aseprite,
+        image: &Image,
+        overrides: &HashMap<String, f64>,
+    ) -> Self {
+        let mut tileset = Self::new(aseprite, image);
+        for tile in tileset.tiles.iter_mut() {
+            if let Some(&weight) = overrides.get(&tile.slice_name) {
+                tile.weight = weight;
+            }
+        }
         tileset
     }
+
+    /// 手描きのタイルシートを用意する代わりに、1枚のサンプル画像から重なり合い
+    /// (overlapping)モデルでタイルセットを構築する近道です。`n x n` のウィンドウを
+    /// スライドしてパターンを抽出し、出現回数を重みとして隣接関係を導きます。内部では
+    /// [`overlapping::tileset_from_image`] をトーラス状ラップ・対称バリアントなしの
+    /// 既定値で呼び出すので、それらを有効にしたい場合は直接そちらを呼んでください。
+    /// 返り値の `Vec<overlapping::Pattern>` は [`overlapping::render_to_image`] に渡して、
+    /// 崩壊済みのセルから画像を書き出す際に使います
+    pub fn from_overlapping(image: &Image, n: usize) -> (Self, Vec<overlapping::Pattern>) {
+        overlapping::tileset_from_image(image, n, false, false)
+    }
+}
+
+/// 隣接する4方向のソケットが完全に一致するタイルの数を数え、
+/// その出現回数をそのままタイルの重みとします。`skip_slices` に挙げたスライス名の
+/// タイルは対称性クラスの指定によって既に重みが割り当て済みなので、ここでは上書きしません
+fn assign_weights_by_frequency(tileset: &mut Tileset, skip_slices: &HashSet<&str>) {
+    let signatures: Vec<(Vec<usize>, Vec<usize>, Vec<usize>, Vec<usize>)> = tileset
+        .tiles
+        .iter()
+        .map(|tile| {
+            let mut up = tile.up.clone();
+            let mut right = tile.right.clone();
+            let mut down = tile.down.clone();
+            let mut left = tile.left.clone();
+            up.sort_unstable();
+            right.sort_unstable();
+            down.sort_unstable();
+            left.sort_unstable();
+            (up, right, down, left)
+        })
+        .collect();
+
+    let weights: Vec<f64> = signatures
+        .iter()
+        .map(|signature| signatures.iter().filter(|other| *other == signature).count() as f64)
+        .collect();
+
+    for (tile, weight) in tileset.tiles.iter_mut().zip(weights) {
+        if skip_slices.contains(tile.slice_name.as_str()) {
+            continue;
+        }
+        tile.weight = weight;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -112,8 +305,144 @@ impl Tileset {
             tileset: self.clone(),
             cells,
             dimension,
+            max_backtracks: DEFAULT_MAX_BACKTRACKS,
+        }
+    }
+}
+
+/// 手描きされた各スライスに対して、4回の90度回転と左右反転の組み合わせで
+/// 最大8個のバリアントを生成します。`symmetries` にスライス名の対称性クラスが
+/// 指定されている場合は、ピクセル比較を行わずそのクラスが許す数(`Symmetry::variant_count`、
+/// 実際に異なる絵柄になる向きの数)だけバリアントを機械的に生成して重みを均等に割ります。
+/// 指定がないスライスは従来どおり、回転・反転後のピクセルが元のスライスと一致してしまう
+/// 対称なタイル(例えば無地のタイル)を自動的に検出して重複を避けます
+fn generate_symmetry_variants(
+    tiles: &mut Vec<Tile>,
+    image: &Image,
+    tile_size: u32,
+    symmetries: &HashMap<String, Symmetry>,
+) {
+    let bases = tiles.clone();
+
+    for base in &bases {
+        if let Some(&symmetry) = symmetries.get(&base.slice_name) {
+            let weight = 1.0 / symmetry.variant_count() as f64;
+            base_tile_weight(tiles, &base.slice_name, weight);
+
+            for &(rotation, flip) in symmetry.variant_transforms() {
+                let mut variant = Tile::with_variant(base, rotation, flip);
+                variant.weight = weight;
+                tiles.push(variant);
+            }
+            continue;
+        }
+
+        let mut signatures = vec![tile_pixel_signature(image, base, tile_size)];
+
+        for &rotation in &Rotation::ALL {
+            for flip in [false, true] {
+                if rotation == Rotation::R0 && !flip {
+                    // 無回転・無反転は元のスライスそのものなので生成しない
+                    continue;
+                }
+
+                let variant = Tile::with_variant(base, rotation, flip);
+                let signature = tile_pixel_signature(image, &variant, tile_size);
+
+                if signatures.contains(&signature) {
+                    // 回転・反転しても見た目が変わらない対称タイルは重複を避ける
+                    continue;
+                }
+
+                signatures.push(signature);
+                tiles.push(variant);
+            }
+        }
+    }
+}
+
+/// `generate_symmetry_variants` が対称性クラス指定のある基底スライスの重みを
+/// バリアントと揃えて均等に割るための補助関数です
+fn base_tile_weight(tiles: &mut [Tile], slice_name: &str, weight: f64) {
+    if let Some(base) = tiles
+        .iter_mut()
+        .find(|tile| tile.slice_name == slice_name && tile.rotation == Rotation::R0 && !tile.flip)
+    {
+        base.weight = weight;
+    }
+}
+
+/// タイルのすべてのピクセルを、回転・反転を適用した上でサンプリングした一覧です。
+/// 同じ絵柄のバリアントを検出するために使います
+fn tile_pixel_signature(image: &Image, tile: &Tile, tile_size: u32) -> Vec<Color> {
+    let mut pixels = Vec::with_capacity((tile_size * tile_size) as usize);
+    for y in 0..tile_size {
+        for x in 0..tile_size {
+            pixels.push(sample_variant_color(image, tile, tile_size, x, y));
+        }
+    }
+    pixels
+}
+
+/// タイルのローカル座標 `(x, y)` (回転・反転適用後)に対応する、元スライス内の座標を求めます
+fn source_coords(x: u32, y: u32, tile_size: u32, rotation: Rotation, flip: bool) -> (u32, u32) {
+    let (x, y) = if flip { (tile_size - 1 - x, y) } else { (x, y) };
+
+    match rotation {
+        Rotation::R0 => (x, y),
+        Rotation::R90 => (y, tile_size - 1 - x),
+        Rotation::R180 => (tile_size - 1 - x, tile_size - 1 - y),
+        Rotation::R270 => (tile_size - 1 - y, x),
+    }
+}
+
+/// タイルのローカル座標 `(x, y)` の色を、回転・反転を適用したうえで元画像から取得します
+fn sample_variant_color(image: &Image, tile: &Tile, tile_size: u32, x: u32, y: u32) -> Color {
+    let (sx, sy) = source_coords(x, y, tile_size, tile.rotation, tile.flip);
+    image
+        .get_color_at(tile.rect.min.x as u32 + sx, tile.rect.min.y as u32 + sy)
+        .unwrap()
+}
+
+/// タイルの辺です。隣接関係の判定はこの4辺同士の比較で行います
+#[derive(Clone, Copy)]
+enum Edge {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+/// 辺に沿った `t` 番目の点の、タイルローカル座標を返します
+fn edge_point(edge: Edge, t: u32, tile_size: u32) -> (u32, u32) {
+    match edge {
+        Edge::Top => (t, 0),
+        Edge::Bottom => (t, tile_size - 1),
+        Edge::Left => (0, t),
+        Edge::Right => (tile_size - 1, t),
+    }
+}
+
+/// 2つのタイルの辺を(それぞれの回転・反転を適用したうえで)比較し、
+/// 完全に一致するかどうかを調べます
+fn compare_edge(
+    image: &Image,
+    current: &Tile,
+    current_edge: Edge,
+    dest: &Tile,
+    dest_edge: Edge,
+    tile_size: u32,
+) -> bool {
+    for t in 0..tile_size {
+        let (cx, cy) = edge_point(current_edge, t, tile_size);
+        let (dx, dy) = edge_point(dest_edge, t, tile_size);
+        let source_color = sample_variant_color(image, current, tile_size, cx, cy);
+        let dest_color = sample_variant_color(image, dest, tile_size, dx, dy);
+        if source_color != dest_color {
+            return false;
         }
     }
+    true
 }
 
 /// 他のタイルと辺のピクセルを比較し、
@@ -123,205 +452,203 @@ pub fn generating_adjacency_rules(tiles: &mut Tileset, image: &Image, tile_size:
     for current in tiles.tiles.iter_mut() {
         for (dest_index, dest) in cloned.tiles.iter().enumerate() {
             // 上辺
-            if compare_edge(
-                &image,
-                current.rect.min.x as u32,
-                current.rect.min.y as u32,
-                dest.rect.min.x as u32,
-                dest.rect.max.y as u32 - 1,
-                1,
-                0,
-                tile_size,
-            ) {
+            if compare_edge(image, current, Edge::Top, dest, Edge::Bottom, tile_size) {
                 current.up.push(dest_index);
             }
 
             // 下辺
-            if compare_edge(
-                &image,
-                current.rect.min.x as u32,
-                current.rect.max.y as u32 - 1,
-                dest.rect.min.x as u32,
-                dest.rect.min.y as u32,
-                1,
-                0,
-                tile_size,
-            ) {
+            if compare_edge(image, current, Edge::Bottom, dest, Edge::Top, tile_size) {
                 current.down.push(dest_index);
             }
 
             // 左辺
-
-            if compare_edge(
-                &image,
-                current.rect.min.x as u32,
-                current.rect.min.y as u32,
-                dest.rect.max.x as u32 - 1,
-                dest.rect.min.y as u32,
-                0,
-                1,
-                tile_size,
-            ) {
+            if compare_edge(image, current, Edge::Left, dest, Edge::Right, tile_size) {
                 current.left.push(dest_index);
             }
 
             // 右辺
-            if compare_edge(
-                &image,
-                current.rect.max.x as u32 - 1,
-                current.rect.min.y as u32,
-                dest.rect.min.x as u32,
-                dest.rect.min.y as u32,
-                0,
-                1,
-                tile_size,
-            ) {
+            if compare_edge(image, current, Edge::Right, dest, Edge::Left, tile_size) {
                 current.right.push(dest_index);
             }
         }
     }
 }
 
-fn compare_edge(
-    image: &Image,
-    source_x: u32,
-    source_y: u32,
-    dest_x: u32,
-    dest_y: u32,
-    dx: u32,
-    dy: u32,
-    tile_size: u32,
-) -> bool {
-    for i in 0..tile_size {
-        let dxi = dx * i;
-        let dyi = dy * i;
-        let source_color = image.get_color_at(source_x + dxi, source_y + dyi).unwrap();
-        let dest_color = image.get_color_at(dest_x + dxi, dest_y + dyi).unwrap();
-        if source_color != dest_color {
-            return false;
-        }
+/// セルの候補タイルについてのシャノンエントロピーを計算します
+///
+/// `H = ln(W) - (Σ w_i・ln(w_i)) / W` (`W = Σ w_i`)。重みが均一なら従来の
+/// 「候補数が少ないほど低い」という近似に一致しますが、重み付きタイルがある場合は
+/// より正確にその後の崩壊しやすさを反映します
+fn shannon_entropy(cell: &Cell, tileset: &Tileset) -> f64 {
+    let weights: Vec<f64> = cell
+        .sockets
+        .iter()
+        .map(|&socket| tileset.tiles[socket].weight)
+        .collect();
+
+    let total_weight: f64 = weights.iter().sum();
+    if total_weight <= 0.0 {
+        return 0.0;
     }
-    true
+
+    let sum_w_ln_w: f64 = weights.iter().map(|&w| w * w.ln()).sum();
+    total_weight.ln() - sum_w_ln_w / total_weight
 }
 
-fn pick_cell_with_least_entropy(cells: &mut Vec<Cell>) -> Vec<&mut Cell> {
-    let mut grid_copy: Vec<&mut Cell> = Vec::new();
+/// 未崩壊のセルのうち、シャノンエントロピーが最小のセルのインデックスを返します
+///
+/// 同率のセルが複数あるとき常に同じセルが選ばれないよう、微小なノイズを加えてタイブレークします
+fn pick_cell_with_least_entropy(
+    cells: &[Cell],
+    tileset: &Tileset,
+    rng: &mut rand::rngs::StdRng,
+) -> Option<usize> {
+    cells
+        .iter()
+        .filter(|cell| !cell.collapsed)
+        .map(|cell| {
+            let entropy = shannon_entropy(cell, tileset) + 1e-6 * rng.gen::<f64>();
+            (entropy, cell.index)
+        })
+        .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap())
+        .map(|(_, index)| index)
+}
+
+/// 候補タイルの重みに比例した確率でひとつを選びます
+fn weighted_choice(
+    rng: &mut rand::rngs::StdRng,
+    candidates: &[usize],
+    tileset: &Tileset,
+) -> Option<usize> {
+    let total_weight: f64 = candidates.iter().map(|&socket| tileset.tiles[socket].weight).sum();
+    if candidates.is_empty() || total_weight <= 0.0 {
+        return candidates.first().copied();
+    }
 
-    for cell in cells.iter_mut() {
-        if !cell.collapsed {
-            grid_copy.push(cell);
+    let mut target = rng.gen::<f64>() * total_weight;
+    for &socket in candidates {
+        target -= tileset.tiles[socket].weight;
+        if target <= 0.0 {
+            return Some(socket);
         }
     }
-    if grid_copy.is_empty() {
-        return Vec::new();
+    candidates.last().copied()
+}
+
+/// banされていないタイルの中から重み付きでひとつを選んで崩壊させます
+///
+/// 選んだセルにbanされていないタイルが残っていない場合は `None` を返し、
+/// 呼び出し側はその場で矛盾(contradiction)として扱ってバックトラックします
+fn random_selection_of_sockets(
+    rng: &mut rand::rngs::StdRng,
+    cell: &mut Cell,
+    tileset: &Tileset,
+    banned: &HashMap<usize, Vec<usize>>,
+) -> Option<(usize, usize, Cell)> {
+    let before = cell.clone();
+
+    if let Some(banned_tiles) = banned.get(&cell.index) {
+        cell.sockets.retain(|socket| !banned_tiles.contains(socket));
     }
-    grid_copy.sort_by_key(|cell| cell.sockets.len());
 
-    let len = grid_copy[0].sockets.len();
-    let stop_index = grid_copy
-        .iter()
-        .position(|cell| cell.sockets.len() > len)
-        .unwrap_or(grid_copy.len());
+    let pick = match weighted_choice(rng, &cell.sockets, tileset) {
+        Some(pick) => pick,
+        None => {
+            // banされていないタイルが残っていなかった場合は、ban listで間引く前の
+            // 状態にセルを戻す。ここで`collapsed`を立てたまま返すと、呼び出し側の
+            // 巻き戻しがこのセルまで届かず、sockets が空のまま崩壊済み扱いで残り続ける
+            *cell = before;
+            return None;
+        }
+    };
 
-    grid_copy.truncate(stop_index);
-    grid_copy
+    let cell_index = cell.index;
+    cell.collapsed = true;
+    cell.sockets = vec![pick];
+    Some((cell_index, pick, before))
 }
 
-fn random_selection_of_sockets(
-    mut rng: &mut rand::rngs::StdRng,
-    grid_target: &mut Vec<&mut Cell>,
+/// 1手分の崩壊の記録です。伝播が原因でこの手自体が矛盾した場合は、この`Decision`を
+/// 履歴に積む前にその場で巻き戻して選んだタイルをban listへ積むので、ここへ積まれている
+/// `Decision`はいずれも伝播が成功した(= まだ矛盾していない)手です。履歴を遡る巻き戻しが
+/// 必要になるのは、あるセルの候補が尽きて手自体を選べなくなったときで、そのときだけ
+/// ここに積んだ一番上の手を取り出してグリッドを巻き戻し、選んだタイルをそのセルの
+/// ban listに加えて再試行します
+struct Decision {
+    cell_index: usize,
+    tile: usize,
+    /// この手の伝播によって変化した(可能性のある)セルの、変化前のスナップショット
+    snapshot: Vec<(usize, Cell)>,
+}
+
+/// 直前に崩壊したセルから四方に制約を伝播させます(AC-3スタイルのワークリスト伝播)
+///
+/// 変化したセルだけをキューに積み直すので、グリッド全体を毎回再計算する必要がありません。
+/// いずれかのセルのsocketsが空になった場合は矛盾として `false` を返します
+fn propagate(
+    cells: &mut Vec<Cell>,
+    dimension: usize,
+    tileset: &Tileset,
+    start: usize,
+    touched: &mut HashSet<usize>,
+    snapshot: &mut Vec<(usize, Cell)>,
 ) -> bool {
-    if let Some(cell) = grid_target.choose_mut(&mut rng) {
-        (*cell).collapsed = true;
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    queue.push_back(start);
+
+    while let Some(index) = queue.pop_front() {
+        let i = index % dimension;
+        let j = index / dimension;
+
+        let neighbors = [
+            (j > 0, index.wrapping_sub(dimension), "up"),
+            (i < dimension - 1, index + 1, "right"),
+            (j < dimension - 1, index + dimension, "down"),
+            (i > 0, index - 1, "left"),
+        ];
+
+        for (exists, neighbor_index, direction) in neighbors {
+            if !exists || cells[neighbor_index].collapsed {
+                continue;
+            }
 
-        if cell.sockets.is_empty() {
-            return false;
-        }
-        if let Some(&pick) = cell.sockets.choose(&mut rng) {
-            cell.sockets = vec![pick];
-            true
-        } else {
-            false
-        }
-    } else {
-        false
-    }
-}
+            let valid = get_valid_sockets(&cells[index], direction, tileset);
+            let before_len = cells[neighbor_index].sockets.len();
 
-fn wave_collapse(cells: &mut Vec<Cell>, dimension: usize, tileset: &Tileset) {
-    let mut next_grid: Vec<Option<Cell>> = vec![None; dimension * dimension];
-
-    for j in 0..dimension {
-        for i in 0..dimension {
-            let index = i + j * dimension;
-
-            if cells[index].collapsed {
-                next_grid[index] = Some(cells[index].clone());
-            } else {
-                let mut sockets: Vec<usize> = (0..tileset.tiles.len()).collect();
-                // Look up
-                if j > 0 {
-                    cell_collapse(
-                        &mut cells[i + (j - 1) * dimension],
-                        "down",
-                        &mut sockets,
-                        &tileset,
-                    );
-                }
-                // Look right
-                if i < dimension - 1 {
-                    cell_collapse(
-                        &mut cells[i + 1 + j * dimension],
-                        "left",
-                        &mut sockets,
-                        &tileset,
-                    );
-                }
-                // Look down
-                if j < dimension - 1 {
-                    cell_collapse(
-                        &mut cells[i + (j + 1) * dimension],
-                        "up",
-                        &mut sockets,
-                        &tileset,
-                    );
-                }
-                // Look left
-                if i > 0 {
-                    cell_collapse(
-                        &mut cells[i - 1 + j * dimension],
-                        "right",
-                        &mut sockets,
-                        &tileset,
-                    );
-                }
+            if touched.insert(neighbor_index) {
+                snapshot.push((neighbor_index, cells[neighbor_index].clone()));
+            }
 
-                next_grid[index] = Some(Cell::from_list(index, sockets));
+            cells[neighbor_index]
+                .sockets
+                .retain(|socket| valid.contains(socket));
+
+            if cells[neighbor_index].sockets.len() != before_len {
+                if cells[neighbor_index].sockets.is_empty() {
+                    return false;
+                }
+                queue.push_back(neighbor_index);
             }
         }
     }
-    cells.clear();
-    cells.extend(next_grid.into_iter().filter_map(|cell| cell));
-}
 
-/// セルのsocketsのうち、接続不可能なものを削除します
-fn cell_collapse(cell: &Cell, direction: &str, sockets: &mut Vec<usize>, tiles: &Tileset) {
-    let valid_sockets = get_valid_sockets(cell, direction, tiles);
-    sockets.retain(|socket| valid_sockets.contains(socket));
+    true
 }
 
-fn get_valid_sockets(cell: &Cell, direction: &str, tiles: &Tileset) -> Vec<usize> {
-    let mut valid_sockets = Vec::new();
+/// セルの候補タイルのそれぞれについて、指定した方向に接続可能なタイルの集合の和を取ります。
+/// `HashSet` で返すことで、`propagate` 側の `retain` がO(1)の所属チェックで済みます
+fn get_valid_sockets(cell: &Cell, direction: &str, tiles: &Tileset) -> HashSet<usize> {
+    let mut valid_sockets = HashSet::new();
 
     for &socket in &cell.sockets {
         let tile = &tiles.tiles[socket];
 
-        let valid = match direction {
-            "up" => tile.up.clone(),
-            "right" => tile.right.clone(),
-            "down" => tile.down.clone(),
-            "left" => tile.left.clone(),
-            _ => Vec::new(),
+        let valid: &[usize] = match direction {
+            "up" => &tile.up,
+            "right" => &tile.right,
+            "down" => &tile.down,
+            "left" => &tile.left,
+            _ => &[],
         };
 
         valid_sockets.extend(valid);
@@ -334,26 +661,111 @@ impl Grid {
         Tileset::new(aseprite, image).create_grid(dimension)
     }
 
+    /// バックトラックの上限を変更したビルダーを返します
+    pub fn with_max_backtracks(mut self, max_backtracks: usize) -> Self {
+        self.max_backtracks = max_backtracks;
+        self
+    }
+
     pub fn collapse_with(&mut self, mut rng: &mut rand::rngs::StdRng) {
         let mut cells = self.cells.clone();
 
-        loop {
-            // エントロピーの低い(socketsが少ない、最も選択肢の少ない)セルを選択
-            let mut low_entropy_grid = pick_cell_with_least_entropy(&mut cells);
+        // 矛盾が起きたときにどこまで巻き戻すかを覚えておく履歴スタックと、
+        // セルごとに「このタイルは行き止まりだった」と分かったものを記録するban list
+        let mut history: Vec<Decision> = Vec::new();
+        let mut banned: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut backtracks = 0usize;
 
-            if low_entropy_grid.is_empty() {
-                break;
-            }
+        loop {
+            // シャノンエントロピーが最小のセルを選択
+            let cell_index = match pick_cell_with_least_entropy(&cells, &self.tileset, &mut rng) {
+                Some(index) => index,
+                None => break,
+            };
+
+            // 重みに応じて候補からひとつを選択して崩壊させる
+            let collapsed = random_selection_of_sockets(
+                &mut rng,
+                &mut cells[cell_index],
+                &self.tileset,
+                &banned,
+            );
+
+            // 今回の手が原因で矛盾したのか(セルと選んだタイルが分かっている)、
+            // それとも候補が尽きて手自体を選べなかったのかを区別して扱う
+            let exhausted = collapsed.is_none();
+            let propagation_contradiction = match collapsed {
+                None => None,
+                Some((cell_index, tile, before)) => {
+                    let mut touched = HashSet::new();
+                    touched.insert(cell_index);
+                    let mut snapshot = vec![(cell_index, before)];
+
+                    let ok = propagate(
+                        &mut cells,
+                        self.dimension,
+                        &self.tileset,
+                        cell_index,
+                        &mut touched,
+                        &mut snapshot,
+                    );
 
-            // 候補からひとつをランダムに選択
-            if !random_selection_of_sockets(&mut rng, &mut low_entropy_grid) {
-                // 候補が見つからない場合は最初からやり直し
-                cells = self.cells.clone();
-                // warn!("restart");
-                continue;
+                    if ok {
+                        history.push(Decision {
+                            cell_index,
+                            tile,
+                            snapshot,
+                        });
+                        None
+                    } else {
+                        Some((cell_index, tile, snapshot))
+                    }
+                }
+            };
+
+            if let Some((cell_index, tile, snapshot)) = propagation_contradiction {
+                backtracks += 1;
+
+                if backtracks > self.max_backtracks {
+                    // バックトラックの予算を使い切った場合は逐次的な巻き戻しを諦めて
+                    // 最初から完全にやり直す
+                    cells = self.cells.clone();
+                    banned.clear();
+                    history.clear();
+                    backtracks = 0;
+                    // warn!("restart");
+                } else {
+                    // 伝播が原因で矛盾したのはこの手そのものなので、この手の巻き戻しだけで
+                    // 済ませる。履歴は1つも消費せず、選んだタイルをこのセルのban listに
+                    // 加える。このセルは次に選ばれたとき、banされていないタイルで再試行される
+                    for (index, snapshot_cell) in snapshot {
+                        cells[index] = snapshot_cell;
+                    }
+                    banned.entry(cell_index).or_default().push(tile);
+                }
+            } else if exhausted {
+                backtracks += 1;
+
+                if history.is_empty() || backtracks > self.max_backtracks {
+                    // 巻き戻す手が残っていないか、バックトラックの予算を使い切った場合は
+                    // 逐次的な巻き戻しを諦めて最初から完全にやり直す
+                    cells = self.cells.clone();
+                    banned.clear();
+                    history.clear();
+                    backtracks = 0;
+                    // warn!("restart");
+                } else if let Some(decision) = history.pop() {
+                    // このセルはもう候補が尽きているので、ひとつ前の手までグリッドを
+                    // 巻き戻し、選んだタイルをban listに加えて再試行する
+                    for (index, snapshot_cell) in decision.snapshot {
+                        cells[index] = snapshot_cell;
+                    }
+                    banned
+                        .entry(decision.cell_index)
+                        .or_default()
+                        .push(decision.tile);
+                }
             }
-
-            wave_collapse(&mut cells, self.dimension, &self.tileset);
         }
 
         self.cells = cells;
@@ -364,35 +776,116 @@ impl Grid {
         self.collapse_with(&mut rng);
     }
 
+    /// [`collapse_with`](Self::collapse_with)のGPU版です。CPU側は引き続き最小エントロピーの
+    /// セルを選んで崩壊させますが、その1セルぶんの制約伝播は `gpu::dispatch_propagation` に
+    /// 渡してコンピュートシェーダ上で行います。マス目の多い大きな盤面でボトルネックになる
+    /// 伝播だけをGPUへ逃がす一方、小さな盤面では引き続き [`collapse_with`](Self::collapse_with)
+    /// がデフォルトのままです。このパスはバックトラックを行わず、矛盾が起きた場合は
+    /// すべてのセルを未崩壊の初期状態に戻して最初からやり直します(呼び出し前に崩壊済みに
+    /// しておいたセルも初期状態に戻ります)
+    #[cfg(feature = "gpu")]
+    pub fn collapse_gpu(
+        &mut self,
+        rng: &mut rand::rngs::StdRng,
+        device: &bevy::render::renderer::RenderDevice,
+        queue: &bevy::render::renderer::RenderQueue,
+        pipeline_cache: &bevy::render::render_resource::PipelineCache,
+        pipeline: &gpu::GpuWfcPipeline,
+        buffers: &gpu::GpuWfcBuffers,
+    ) {
+        let tile_count = self.tileset.tiles.len();
+
+        loop {
+            let cell_index = match pick_cell_with_least_entropy(&self.cells, &self.tileset, rng) {
+                Some(index) => index,
+                None => break,
+            };
+
+            let banned = HashMap::new();
+            let collapsed =
+                random_selection_of_sockets(rng, &mut self.cells[cell_index], &self.tileset, &banned);
+
+            if collapsed.is_none() {
+                // 候補が尽きた場合は最初からやり直す
+                self.cells = self.tileset.create_grid(self.dimension).cells;
+                continue;
+            }
+
+            buffers.upload_cell_masks(queue, &self.cells, tile_count);
+
+            match gpu::dispatch_propagation(
+                device,
+                queue,
+                pipeline_cache,
+                pipeline,
+                buffers,
+                self.dimension,
+                tile_count,
+            ) {
+                Some(sockets_per_cell) => {
+                    for (index, sockets) in sockets_per_cell.into_iter().enumerate() {
+                        if !self.cells[index].collapsed {
+                            self.cells[index].sockets = sockets;
+                        }
+                    }
+                }
+                None => {
+                    // 矛盾が起きたら最初からやり直す
+                    self.cells = self.tileset.create_grid(self.dimension).cells;
+                }
+            }
+        }
+    }
+
     pub fn spawn(&self, commands: &mut Commands, aseprite: &Handle<Aseprite>) {
         for cell in self.cells.iter() {
+            let tile = &self.tileset.tiles[cell.sockets[0]];
             commands.spawn((
                 AseSpriteSlice {
                     aseprite: aseprite.clone(),
-                    name: self.tileset.tiles[cell.sockets[0]].slice_name.clone(),
+                    name: tile.slice_name.clone(),
                 },
-                Transform::from_translation(Vec3::new(
-                    (cell.index % self.dimension) as f32 * self.tileset.tile_size as f32,
-                    (cell.index / self.dimension) as f32 * self.tileset.tile_size as f32 * -1.0,
-                    0.0,
-                )),
+                self.cell_transform(cell, tile),
             ));
         }
     }
 
     pub fn spawn_with_world(&self, commands: &mut World, aseprite: &Handle<Aseprite>) {
         for cell in self.cells.iter() {
+            let tile = &self.tileset.tiles[cell.sockets[0]];
             commands.spawn((
                 AseSpriteSlice {
                     aseprite: aseprite.clone(),
-                    name: self.tileset.tiles[cell.sockets[0]].slice_name.clone(),
+                    name: tile.slice_name.clone(),
                 },
-                Transform::from_translation(Vec3::new(
-                    (cell.index % self.dimension) as f32 * self.tileset.tile_size as f32,
-                    (cell.index / self.dimension) as f32 * self.tileset.tile_size as f32 * -1.0,
-                    0.0,
-                )),
+                self.cell_transform(cell, tile),
             ));
         }
     }
+
+    /// セルの盤面上の位置と、選ばれたタイルの回転・反転バリアントを反映したTransformを作ります
+    fn cell_transform(&self, cell: &Cell, tile: &Tile) -> Transform {
+        let translation = Vec3::new(
+            (cell.index % self.dimension) as f32 * self.tileset.tile_size as f32,
+            (cell.index / self.dimension) as f32 * self.tileset.tile_size as f32 * -1.0,
+            0.0,
+        );
+
+        let angle = match tile.rotation {
+            Rotation::R0 => 0.0,
+            Rotation::R90 => -std::f32::consts::FRAC_PI_2,
+            Rotation::R180 => std::f32::consts::PI,
+            Rotation::R270 => std::f32::consts::FRAC_PI_2,
+        };
+        // Transformは反転(scale)の後に回転を適用するので、`source_coords` と同じ絵柄に
+        // なるよう、反転を伴うバリアントでは回転の向きを逆にする
+        let angle = if tile.flip { -angle } else { angle };
+
+        let mut transform =
+            Transform::from_translation(translation).with_rotation(Quat::from_rotation_z(angle));
+        if tile.flip {
+            transform.scale.x = -1.0;
+        }
+        transform
+    }
 }
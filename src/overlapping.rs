@@ -0,0 +1,235 @@
+//! 1枚のサンプル画像から学習する「重なり合い(overlapping)モデル」の実装です
+//!
+//! [`crate::Tileset::new`] が使う"simple tiled"モデルは、あらかじめ手描きした
+//! スライスの辺のピクセルを比較して隣接関係を求めますが、こちらはタイルシートを
+//! 用意する代わりに、サンプル画像上をN×Nのウィンドウでスライドして現れるパターンを
+//! すべて収集し、パターン同士の重なり領域が一致するかどうかから隣接関係を導きます。
+//! 生成された [`Tileset`] から `Tileset::create_grid` で作ったグリッドは、既存の
+//! `Grid::collapse_with` でそのまま崩壊させられます
+
+use crate::{Cell, Rotation, Tile, Tileset};
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+};
+use std::collections::HashMap;
+
+/// N×Nの色パターン1つ分です。行優先で `n * n` 個のRGBAピクセルを保持します
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pattern {
+    pub n: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Pattern {
+    fn get(&self, x: usize, y: usize) -> [u8; 4] {
+        self.pixels[y * self.n + x]
+    }
+
+    /// 時計回りに90度回転したパターンを返します
+    fn rotated(&self) -> Pattern {
+        let n = self.n;
+        let mut pixels = vec![[0u8; 4]; n * n];
+        for y in 0..n {
+            for x in 0..n {
+                pixels[y * n + x] = self.get(y, n - 1 - x);
+            }
+        }
+        Pattern { n, pixels }
+    }
+
+    /// 左右反転したパターンを返します
+    fn flipped(&self) -> Pattern {
+        let n = self.n;
+        let mut pixels = vec![[0u8; 4]; n * n];
+        for y in 0..n {
+            for x in 0..n {
+                pixels[y * n + x] = self.get(n - 1 - x, y);
+            }
+        }
+        Pattern { n, pixels }
+    }
+}
+
+fn color_to_bytes(color: Color) -> [u8; 4] {
+    let srgba = color.to_srgba();
+    [
+        (srgba.red * 255.0).round() as u8,
+        (srgba.green * 255.0).round() as u8,
+        (srgba.blue * 255.0).round() as u8,
+        (srgba.alpha * 255.0).round() as u8,
+    ]
+}
+
+/// サンプル画像から `n x n` のパターンを抽出し、出現回数を重みとして持つ [`Tileset`] を
+/// 構築します。`wrap` を真にすると画像の端をトーラス状につなげてサンプリングし、
+/// `include_symmetries` を真にすると各パターンの90度回転・反転も別パターンとして追加します。
+///
+/// 返り値の `Vec<Pattern>` は `Tileset::tiles` と同じ並びで、[`render_to_image`] が
+/// 崩壊済みのセルからピクセルを書き出す際に使います
+pub fn tileset_from_image(
+    image: &Image,
+    n: usize,
+    wrap: bool,
+    include_symmetries: bool,
+) -> (Tileset, Vec<Pattern>) {
+    let width = image.texture_descriptor.size.width as i64;
+    let height = image.texture_descriptor.size.height as i64;
+
+    let sample = |x: i64, y: i64| -> [u8; 4] {
+        let (sx, sy) = if wrap {
+            (x.rem_euclid(width) as u32, y.rem_euclid(height) as u32)
+        } else {
+            (
+                x.clamp(0, width - 1) as u32,
+                y.clamp(0, height - 1) as u32,
+            )
+        };
+        color_to_bytes(image.get_color_at(sx, sy).unwrap())
+    };
+
+    let max_x = if wrap { width } else { width - (n as i64 - 1) };
+    let max_y = if wrap { height } else { height - (n as i64 - 1) };
+
+    let mut pattern_counts: HashMap<Pattern, f64> = HashMap::new();
+
+    for y in 0..max_y.max(0) {
+        for x in 0..max_x.max(0) {
+            let mut pixels = Vec::with_capacity(n * n);
+            for dy in 0..n as i64 {
+                for dx in 0..n as i64 {
+                    pixels.push(sample(x + dx, y + dy));
+                }
+            }
+            let pattern = Pattern { n, pixels };
+
+            for variant in symmetry_variants(&pattern, include_symmetries) {
+                *pattern_counts.entry(variant).or_insert(0.0) += 1.0;
+            }
+        }
+    }
+
+    // `HashMap`の反復順はプロセスごとにランダムなので、パターンのピクセル列で
+    // 並べ替えてタイルのインデックスを安定させ、同じRNGシードからは常に同じ結果を得られるようにする
+    let mut patterns: Vec<Pattern> = pattern_counts.keys().cloned().collect();
+    patterns.sort_by(|a, b| a.pixels.cmp(&b.pixels));
+
+    let mut tiles: Vec<Tile> = patterns
+        .iter()
+        .enumerate()
+        .map(|(index, _)| {
+            let mut tile = Tile::new(format!("pattern-{index}"), Rect::new(0.0, 0.0, n as f32, n as f32));
+            tile.weight = pattern_counts[&patterns[index]];
+            tile.rotation = Rotation::R0;
+            tile.flip = false;
+            tile
+        })
+        .collect();
+
+    // 重なり領域(上下左右にずらした (n - 1) x n あるいは n x (n - 1) の領域)が
+    // 一致するかどうかで隣接関係を求めます
+    for current_index in 0..patterns.len() {
+        for dest_index in 0..patterns.len() {
+            if overlap_matches(&patterns[current_index], &patterns[dest_index], 0, -1) {
+                tiles[current_index].up.push(dest_index);
+            }
+            if overlap_matches(&patterns[current_index], &patterns[dest_index], 1, 0) {
+                tiles[current_index].right.push(dest_index);
+            }
+            if overlap_matches(&patterns[current_index], &patterns[dest_index], 0, 1) {
+                tiles[current_index].down.push(dest_index);
+            }
+            if overlap_matches(&patterns[current_index], &patterns[dest_index], -1, 0) {
+                tiles[current_index].left.push(dest_index);
+            }
+        }
+    }
+
+    let tileset = Tileset {
+        tiles,
+        tile_size: n as u32,
+    };
+
+    (tileset, patterns)
+}
+
+fn symmetry_variants(pattern: &Pattern, include_symmetries: bool) -> Vec<Pattern> {
+    if !include_symmetries {
+        return vec![pattern.clone()];
+    }
+
+    let mut variants = Vec::with_capacity(8);
+    let mut current = pattern.clone();
+    for _ in 0..4 {
+        variants.push(current.clone());
+        current = current.rotated();
+    }
+
+    let mut current = pattern.flipped();
+    for _ in 0..4 {
+        variants.push(current.clone());
+        current = current.rotated();
+    }
+
+    variants
+}
+
+/// `current` を `(dx, dy)` だけずらした位置に `dest` を置いたとき、重なり合う
+/// `(n - |dx|) x (n - |dy|)` の領域のピクセルがすべて一致するかどうかを調べます
+fn overlap_matches(current: &Pattern, dest: &Pattern, dx: i32, dy: i32) -> bool {
+    let n = current.n as i32;
+    let overlap_w = n - dx.abs();
+    let overlap_h = n - dy.abs();
+
+    for y in 0..overlap_h {
+        for x in 0..overlap_w {
+            let (cx, cy) = (if dx >= 0 { x + dx } else { x }, if dy >= 0 { y + dy } else { y });
+            let (dxp, dyp) = (if dx >= 0 { x } else { x - dx }, if dy >= 0 { y } else { y - dy });
+
+            if current.get(cx as usize, cy as usize) != dest.get(dxp as usize, dyp as usize) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// 崩壊済みのグリッドを1枚の画像として書き出します。最後の行・列以外は各セルの
+/// 左上1ピクセルだけを採用し、最後の行・列だけパターン全体を書き出すことで、
+/// `dimension x dimension` 個のパターンから `(dimension + n - 1)` 四方の画像を再構成します
+pub fn render_to_image(cells: &[Cell], patterns: &[Pattern], dimension: usize, n: usize) -> Image {
+    let output_size = dimension + n - 1;
+    let mut pixels = vec![[0u8; 4]; output_size * output_size];
+
+    for cell in cells {
+        let i = cell.index % dimension;
+        let j = cell.index / dimension;
+        let pattern = &patterns[cell.sockets[0]];
+
+        let w = if i == dimension - 1 { n } else { 1 };
+        let h = if j == dimension - 1 { n } else { 1 };
+
+        for y in 0..h {
+            for x in 0..w {
+                pixels[(j + y) * output_size + (i + x)] = pattern.get(x, y);
+            }
+        }
+    }
+
+    let data: Vec<u8> = pixels.into_iter().flatten().collect();
+
+    Image::new(
+        Extent3d {
+            width: output_size as u32,
+            height: output_size as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+    )
+}